@@ -0,0 +1,526 @@
+use crate::cpu::{AddressingMode, PROGRAM_START};
+use crate::opcodes::{self, OpCode};
+use std::collections::HashMap;
+use std::fmt;
+
+const BRANCHES: [&str; 8] = ["BCC", "BCS", "BEQ", "BMI", "BNE", "BPL", "BVC", "BVS"];
+
+/// Output of `assemble`: the bytes starting at `origin` plus every label's
+/// address.
+#[derive(Debug)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownInstruction { line: usize, mnemonic: String },
+    InvalidOperand { line: usize, operand: String },
+    UnknownDirective { line: usize, directive: String },
+    UndefinedLabel { line: usize, label: String },
+    DuplicateLabel { line: usize, label: String },
+    BranchOutOfRange { line: usize, label: String },
+    OrgBackwards { line: usize, org: u16 },
+    AddressOverflow { line: usize },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmError::UnknownInstruction { line, mnemonic } => {
+                write!(f, "line {}: unknown instruction {}", line, mnemonic)
+            }
+            AsmError::InvalidOperand { line, operand } => {
+                write!(f, "line {}: invalid operand {}", line, operand)
+            }
+            AsmError::UnknownDirective { line, directive } => {
+                write!(f, "line {}: unknown directive {}", line, directive)
+            }
+            AsmError::UndefinedLabel { line, label } => {
+                write!(f, "line {}: undefined label {}", line, label)
+            }
+            AsmError::DuplicateLabel { line, label } => {
+                write!(f, "line {}: label {} defined twice", line, label)
+            }
+            AsmError::BranchOutOfRange { line, label } => {
+                write!(f, "line {}: branch to {} out of range", line, label)
+            }
+            AsmError::OrgBackwards { line, org } => {
+                write!(
+                    f,
+                    "line {}: .org {:#06x} is behind the current address",
+                    line, org
+                )
+            }
+            AsmError::AddressOverflow { line } => {
+                write!(f, "line {}: output runs past $ffff", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+enum Target {
+    Value(u16),
+    Label(String),
+}
+
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+enum Operand {
+    Implied,
+    Immediate(u8),
+    Address(Target, Index),
+    Indirect(Target),
+    IndirectX(u8),
+    IndirectY(u8),
+}
+
+enum Statement {
+    Instruction { mnemonic: String, operand: String },
+    Byte(Vec<u8>),
+    Org(u16),
+}
+
+struct Line {
+    number: usize,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+/// Assembles a simple line-based 6502 source. Supports `label:` definitions,
+/// `;` comments, the `.org` and `.byte` directives, and the instructions and
+/// addressing modes in the opcode table. Numbers are decimal, `$hex` or
+/// `%binary`. Output starts at 0x0600 unless the first statement is `.org`.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(i + 1, text))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // first pass: sizes only, to find label addresses
+    let mut symbols = HashMap::new();
+    let origin = match lines.iter().find_map(|l| l.statement.as_ref()) {
+        Some(Statement::Org(org)) => *org,
+        _ => PROGRAM_START,
+    };
+    // kept wider than u16 so a program may end exactly at $ffff
+    let mut pc = origin as usize;
+    for line in &lines {
+        if let Some(Statement::Org(org)) = &line.statement {
+            if (*org as usize) < pc {
+                return Err(AsmError::OrgBackwards {
+                    line: line.number,
+                    org: *org,
+                });
+            }
+            pc = *org as usize;
+        }
+        if let Some(label) = &line.label {
+            if symbols.insert(label.clone(), pc as u16).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line: line.number,
+                    label: label.clone(),
+                });
+            }
+        }
+        let size = match &line.statement {
+            Some(Statement::Instruction { mnemonic, operand }) => {
+                encode(line.number, mnemonic, operand, pc as u16, None)?.len()
+            }
+            Some(Statement::Byte(bytes)) => bytes.len(),
+            _ => 0,
+        };
+        pc = pc
+            .checked_add(size)
+            .filter(|&end| end <= 0x10000)
+            .ok_or(AsmError::AddressOverflow { line: line.number })?;
+    }
+
+    // second pass: emit with labels resolved
+    let mut bytes = vec![];
+    for line in &lines {
+        let pc = origin.wrapping_add(bytes.len() as u16);
+        match &line.statement {
+            Some(Statement::Instruction { mnemonic, operand }) => {
+                bytes.extend(encode(line.number, mnemonic, operand, pc, Some(&symbols))?);
+            }
+            Some(Statement::Byte(data)) => bytes.extend_from_slice(data),
+            Some(Statement::Org(org)) => bytes.resize((org - origin) as usize, 0),
+            None => {}
+        }
+    }
+
+    Ok(Assembly {
+        origin,
+        bytes,
+        symbols,
+    })
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line, AsmError> {
+    let mut rest = match text.find(';') {
+        Some(pos) => &text[..pos],
+        None => text,
+    }
+    .trim();
+
+    let mut label = None;
+    if let Some(pos) = rest.find(':') {
+        let name = rest[..pos].trim();
+        if is_identifier(name) {
+            label = Some(name.to_string());
+            rest = rest[pos + 1..].trim();
+        }
+    }
+
+    if rest.is_empty() {
+        return Ok(Line {
+            number,
+            label,
+            statement: None,
+        });
+    }
+
+    let (head, tail) = match rest.find(char::is_whitespace) {
+        Some(pos) => (&rest[..pos], rest[pos..].trim()),
+        None => (rest, ""),
+    };
+    let invalid = || AsmError::InvalidOperand {
+        line: number,
+        operand: tail.to_string(),
+    };
+
+    let statement = match head.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(parse_number(tail).ok_or_else(invalid)?),
+        ".byte" => {
+            let mut bytes = vec![];
+            for item in tail.split(',') {
+                match parse_number(item.trim()) {
+                    Some(value) if value <= 0xff => bytes.push(value as u8),
+                    _ => return Err(invalid()),
+                }
+            }
+            Statement::Byte(bytes)
+        }
+        directive if directive.starts_with('.') => {
+            return Err(AsmError::UnknownDirective {
+                line: number,
+                directive: head.to_string(),
+            })
+        }
+        _ => Statement::Instruction {
+            mnemonic: head.to_ascii_uppercase(),
+            operand: tail.split_whitespace().collect(),
+        },
+    };
+
+    Ok(Line {
+        number,
+        label,
+        statement: Some(statement),
+    })
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix('%') {
+        u16::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_target(text: &str) -> Option<Target> {
+    if is_identifier(text) {
+        Some(Target::Label(text.to_string()))
+    } else {
+        parse_number(text).map(Target::Value)
+    }
+}
+
+fn parse_zero_page(text: &str) -> Option<u8> {
+    match parse_number(text) {
+        Some(value) if value <= 0xff => Some(value as u8),
+        _ => None,
+    }
+}
+
+fn parse_operand(text: &str) -> Option<Operand> {
+    let upper = text.to_ascii_uppercase();
+
+    if text.is_empty() || upper == "A" {
+        return Some(Operand::Implied);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return parse_zero_page(value).map(Operand::Immediate);
+    }
+    if text.starts_with('(') {
+        let len = text.len();
+        if upper.ends_with(",X)") {
+            return parse_zero_page(&text[1..len - 3]).map(Operand::IndirectX);
+        }
+        if upper.ends_with("),Y") {
+            return parse_zero_page(&text[1..len - 3]).map(Operand::IndirectY);
+        }
+        if text.ends_with(')') {
+            return parse_target(&text[1..len - 1]).map(Operand::Indirect);
+        }
+        return None;
+    }
+
+    let (base, index) = if upper.ends_with(",X") {
+        (&text[..text.len() - 2], Index::X)
+    } else if upper.ends_with(",Y") {
+        (&text[..text.len() - 2], Index::Y)
+    } else {
+        (text, Index::None)
+    };
+    parse_target(base).map(|target| Operand::Address(target, index))
+}
+
+fn find_opcode(mnemonic: &str, mode: AddressingMode) -> Option<&'static OpCode> {
+    opcodes::CPU_OPS_CODES
+        .iter()
+        .find(|op| op.mnemonic.trim_start_matches('*') == mnemonic && op.mode == mode)
+}
+
+/// Encodes one instruction at `pc`. With `symbols` set to `None` labels
+/// resolve to 0, which is enough to size the instruction in the first pass.
+fn encode(
+    line: usize,
+    mnemonic: &str,
+    operand_text: &str,
+    pc: u16,
+    symbols: Option<&HashMap<String, u16>>,
+) -> Result<Vec<u8>, AsmError> {
+    let invalid = || AsmError::InvalidOperand {
+        line,
+        operand: operand_text.to_string(),
+    };
+    let resolve = |target: &Target| -> Result<u16, AsmError> {
+        match (target, symbols) {
+            (Target::Value(value), _) => Ok(*value),
+            (Target::Label(_), None) => Ok(0),
+            (Target::Label(label), Some(symbols)) => {
+                symbols
+                    .get(label)
+                    .copied()
+                    .ok_or_else(|| AsmError::UndefinedLabel {
+                        line,
+                        label: label.clone(),
+                    })
+            }
+        }
+    };
+    let with_address = |code: u8, addr: u16| vec![code, addr as u8, (addr >> 8) as u8];
+
+    let operand = parse_operand(operand_text).ok_or_else(invalid)?;
+
+    if BRANCHES.contains(&mnemonic) {
+        let target = match &operand {
+            Operand::Address(target, Index::None) => target,
+            _ => return Err(invalid()),
+        };
+        let code = find_opcode(mnemonic, AddressingMode::NoneAddressing)
+            .unwrap()
+            .code;
+        let offset = match symbols {
+            None => 0,
+            Some(_) => resolve(target)? as i32 - (pc as i32 + 2),
+        };
+        if offset < i8::MIN as i32 || offset > i8::MAX as i32 {
+            return Err(AsmError::BranchOutOfRange {
+                line,
+                label: operand_text.to_string(),
+            });
+        }
+        return Ok(vec![code, offset as i8 as u8]);
+    }
+
+    match (mnemonic, &operand) {
+        ("JMP", Operand::Address(target, Index::None)) => {
+            return Ok(with_address(0x4c, resolve(target)?))
+        }
+        ("JMP", Operand::Indirect(target)) => return Ok(with_address(0x6c, resolve(target)?)),
+        ("JSR", Operand::Address(target, Index::None)) => {
+            return Ok(with_address(0x20, resolve(target)?))
+        }
+        ("JMP", _) | ("JSR", _) => return Err(invalid()),
+        _ => {}
+    }
+
+    if !opcodes::CPU_OPS_CODES
+        .iter()
+        .any(|op| op.mnemonic.trim_start_matches('*') == mnemonic)
+    {
+        return Err(AsmError::UnknownInstruction {
+            line,
+            mnemonic: mnemonic.to_string(),
+        });
+    }
+
+    let find = |mode| find_opcode(mnemonic, mode).ok_or_else(invalid);
+    match operand {
+        Operand::Implied => Ok(vec![find(AddressingMode::NoneAddressing)?.code]),
+        Operand::Immediate(value) => Ok(vec![find(AddressingMode::Immediate)?.code, value]),
+        Operand::IndirectX(value) => Ok(vec![find(AddressingMode::Indirect_X)?.code, value]),
+        Operand::IndirectY(value) => Ok(vec![find(AddressingMode::Indirect_Y)?.code, value]),
+        Operand::Indirect(_) => Err(invalid()),
+        Operand::Address(target, index) => {
+            let (zero_page_mode, absolute_mode) = match index {
+                Index::None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                Index::X => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                Index::Y => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+            };
+            if let Target::Value(value) = target {
+                if value <= 0xff {
+                    if let Some(op) = find_opcode(mnemonic, zero_page_mode) {
+                        return Ok(vec![op.code, value as u8]);
+                    }
+                }
+            }
+            let addr = resolve(&target)?;
+            Ok(with_address(find(absolute_mode)?.code, addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_assemble_and_run_with_forward_label() {
+        let source = "
+            ; count X up to 5, then store it
+                    LDX #$00
+            loop:   INX
+                    CPX #5
+                    BNE loop
+                    JMP done     ; forward reference
+                    .byte $ff, $fe
+            done:   STX $10
+                    BRK
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.origin, 0x0600);
+        assert_eq!(assembly.symbols["loop"], 0x0602);
+        assert_eq!(assembly.symbols["done"], 0x060c);
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xa2, 0x00, 0xe8, 0xe0, 0x05, 0xd0, 0xfb, 0x4c, 0x0c, 0x06, 0xff, 0xfe, 0x86, 0x10,
+                0x00
+            ]
+        );
+
+        let mut cpu = CPU::new();
+        cpu.load_and_run(assembly.bytes).unwrap();
+        assert_eq!(cpu.mem_read(0x10), 5);
+    }
+
+    #[test]
+    fn test_assemble_addressing_modes() {
+        let source = "
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $1234,Y
+            LDA ($20,X)
+            LDA ($20),Y
+            ASL A
+            ROL
+            JMP ($0120)
+            ANC #%10000000
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xa5, 0x10, 0xb5, 0x10, 0xb6, 0x10, 0xad, 0x34, 0x12, 0xbd, 0x34, 0x12, 0xb9, 0x34,
+                0x12, 0xa1, 0x20, 0xb1, 0x20, 0x0a, 0x2a, 0x6c, 0x20, 0x01, 0x0b, 0x80
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_org_pads_output() {
+        let assembly = assemble(".org $8000\nNOP\n.org $8004\nstart: BRK").unwrap();
+        assert_eq!(assembly.origin, 0x8000);
+        assert_eq!(assembly.bytes, vec![0xea, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(assembly.symbols["start"], 0x8004);
+    }
+
+    #[test]
+    fn test_assemble_output_may_end_at_top_of_memory() {
+        let assembly = assemble(".org $fffa\n.byte 0, 6, 0, 6, 0, 6").unwrap();
+        assert_eq!(assembly.origin, 0xfffa);
+        assert_eq!(assembly.bytes.len(), 6);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("NOP\nFOO #1").unwrap_err(),
+            AsmError::UnknownInstruction {
+                line: 2,
+                mnemonic: "FOO".to_string()
+            }
+        );
+        assert_eq!(
+            assemble("JMP nowhere").unwrap_err(),
+            AsmError::UndefinedLabel {
+                line: 1,
+                label: "nowhere".to_string()
+            }
+        );
+        assert_eq!(
+            assemble("STA #$10").unwrap_err(),
+            AsmError::InvalidOperand {
+                line: 1,
+                operand: "#$10".to_string()
+            }
+        );
+        assert_eq!(
+            assemble("a: NOP\na: NOP").unwrap_err(),
+            AsmError::DuplicateLabel {
+                line: 2,
+                label: "a".to_string()
+            }
+        );
+        assert_eq!(
+            assemble(".org $fffe\nNOP\nJMP $0600").unwrap_err(),
+            AsmError::AddressOverflow { line: 3 }
+        );
+        assert_eq!(
+            assemble(".org $ffff\n.byte 1, 2").unwrap_err(),
+            AsmError::AddressOverflow { line: 2 }
+        );
+        assert_eq!(
+            format!("{}", assemble(".word 1").unwrap_err()),
+            "line 1: unknown directive .word"
+        );
+    }
+}
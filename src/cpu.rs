@@ -1,5 +1,6 @@
 use crate::opcodes;
 use std::collections::HashMap;
+use std::fmt;
 
 bitflags! {
   pub struct CpuFlags: u8 {
@@ -16,7 +17,21 @@ bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
+const IRQ_VECTOR: u16 = 0xFFFE;
+/// Where `load` places programs and points the reset vector.
+pub(crate) const PROGRAM_START: u16 = 0x0600;
+/// `load` writes the reset vector at 0xFFFC, so programs must end below it.
+const MAX_PROGRAM_LEN: usize = 0xFFFC - PROGRAM_START as usize;
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+const STATE_MAGIC: &[u8; 4] = b"RES\0";
+const STATE_VERSION: u8 = 1;
+const STATE_HEADER_LEN: usize = 5;
+const STATE_LEN: usize = STATE_HEADER_LEN + 8 + 0x10000;
+
+#[derive(Clone)]
 pub struct CPU {
     pub register_a: u8,
     pub register_x: u8,
@@ -24,10 +39,160 @@ pub struct CPU {
     pub status: CpuFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    memory: [u8; 0xffff],
+    irq_line: bool,
+    debug_stack_guard: bool,
+    stack_wrapped: bool,
+    unsupported_opcode_policy: UnsupportedOpcodePolicy,
+    instructions_retired: u64,
+    opcode_histogram: [u64; 256],
+    memory: [u8; 0x10000],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    UnsupportedOpcode { opcode: u8, pc: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnsupportedOpcode { opcode, pc } => {
+                write!(f, "unsupported opcode {:#04x} at PC {:#06x}", opcode, pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// Metadata about an instruction executed by `CPU::step_info`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutedInstruction {
+    pub opcode: &'static opcodes::OpCode,
+    pub pc: u16,
+    /// Effective address of the operand, or the target of a jump or branch.
+    pub operand_address: Option<u16>,
+    /// Cycles taken, including page-cross and taken-branch penalties.
+    pub cycles: u8,
+    pub stop_reason: Option<StopReason>,
+}
+
+/// Programmer-visible registers, as a plain value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub program_counter: u16,
+}
+
+/// Snapshot of the CPU taken just before an instruction is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceState {
+    pub registers: CpuState,
+    pub opcode: u8,
+    pub instructions_retired: u64,
+}
+
+impl TraceState {
+    fn fold_into(&self, hash: u64) -> u64 {
+        let regs = &self.registers;
+        let hash = fnv1a(hash, &regs.program_counter.to_le_bytes());
+        let hash = fnv1a(
+            hash,
+            &[
+                self.opcode,
+                regs.register_a,
+                regs.register_x,
+                regs.register_y,
+                regs.status,
+                regs.stack_pointer,
+            ],
+        );
+        fnv1a(hash, &self.instructions_retired.to_le_bytes())
+    }
+}
+
+fn page_crossed(a: u16, b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuLoadError {
+    ProgramTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for CpuLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuLoadError::ProgramTooLarge { len, max } => write!(
+                f,
+                "program of {} bytes would overwrite the reset vector (max {})",
+                len, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuLoadError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated { expected: usize, actual: usize },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state (bad magic)"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::Truncated { expected, actual } => write!(
+                f,
+                "save state truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Break,
+    StackWrap,
+    UnsupportedOpcode(CpuError),
+    /// `run_bounded` executed its maximum number of instructions.
+    InstructionLimit,
+    /// `run_bounded`'s predicate returned true.
+    Predicate,
 }
 
-#[derive(Debug)]
+/// What the run loop does when it fetches an opcode the core does not
+/// implement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedOpcodePolicy {
+    /// Stop with `StopReason::UnsupportedOpcode`, leaving PC on the opcode.
+    Halt,
+    /// Treat it as a one-byte NOP and carry on.
+    SkipAsNop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -49,7 +214,7 @@ pub trait Mem {
 
     fn mem_read_u16(&self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
@@ -57,7 +222,7 @@ pub trait Mem {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 }
 
@@ -80,37 +245,47 @@ impl CPU {
             status: CpuFlags::from_bits_truncate(0b100100),
             program_counter: 0,
             stack_pointer: STACK_RESET,
-            memory: [0; 0xffff],
+            irq_line: false,
+            debug_stack_guard: false,
+            stack_wrapped: false,
+            unsupported_opcode_policy: UnsupportedOpcodePolicy::Halt,
+            instructions_retired: 0,
+            opcode_histogram: [0; 256],
+            memory: [0; 0x10000],
         }
     }
 
     fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+        self.get_absolute_address(mode, self.program_counter)
+    }
+
+    fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
         match mode {
-            AddressingMode::Immediate => self.program_counter,
-            AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
-            AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
+            AddressingMode::Immediate => addr,
+            AddressingMode::ZeroPage => self.mem_read(addr) as u16,
+            AddressingMode::Absolute => self.mem_read_u16(addr),
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(self.program_counter);
+                let pos = self.mem_read(addr);
 
                 pos.wrapping_add(self.register_x) as u16
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(self.program_counter);
+                let pos = self.mem_read(addr);
 
                 pos.wrapping_add(self.register_y) as u16
             }
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(self.program_counter);
+                let base = self.mem_read_u16(addr);
 
                 base.wrapping_add(self.register_x as u16)
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(self.program_counter);
+                let base = self.mem_read_u16(addr);
 
                 base.wrapping_add(self.register_y as u16)
             }
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(self.program_counter);
+                let base = self.mem_read(addr);
 
                 let ptr: u8 = base.wrapping_add(self.register_x);
                 let lo = self.mem_read(ptr as u16);
@@ -118,7 +293,7 @@ impl CPU {
                 (hi as u16) << 8 | (lo as u16)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(self.program_counter);
+                let base = self.mem_read(addr);
 
                 let lo = self.mem_read(base as u16);
                 let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
@@ -194,13 +369,13 @@ impl CPU {
     fn sbc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(&mode);
         let data = self.mem_read(addr);
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        self.sbc_inner(data, self.status.contains(CpuFlags::CARRY));
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
-        self.add_to_register_a(value);
+        self.adc_inner(value, self.status.contains(CpuFlags::CARRY));
     }
 
     fn asl_accumulator(&mut self) {
@@ -382,31 +557,34 @@ impl CPU {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    fn add_to_register_a(&mut self, data: u8) {
-        let sum = self.register_a as u16
-            + data as u16
-            + (if self.status.contains(CpuFlags::CARRY) {
-                1
-            } else {
-                0
-            }) as u16;
-
-        let carry = sum > 0xff;
-
-        if carry {
-            self.status.insert(CpuFlags::CARRY);
-        } else {
-            self.status.remove(CpuFlags::CARRY);
-        }
-
+    /// A + value + C. Carry is set when the unsigned sum passes 0xff; overflow
+    /// when both operands share a sign the result doesn't.
+    fn adc_inner(&mut self, value: u8, carry_in: bool) {
+        let sum = self.register_a as u16 + value as u16 + carry_in as u16;
         let result = sum as u8;
 
-        if (data ^ result) & (result ^ self.register_a) & 0x80 != 0 {
-            self.status.insert(CpuFlags::OVERFLOW);
-        } else {
-            self.status.remove(CpuFlags::OVERFLOW);
-        }
+        self.status.set(CpuFlags::CARRY, sum > 0xff);
+        self.status.set(
+            CpuFlags::OVERFLOW,
+            (self.register_a ^ result) & (value ^ result) & 0x80 != 0,
+        );
+        self.set_register_a(result);
+    }
 
+    /// A - value - !C. Carry is cleared when the subtraction borrows; overflow
+    /// is set when the operands differ in sign and the result's sign differs
+    /// from A's.
+    fn sbc_inner(&mut self, value: u8, carry_in: bool) {
+        let borrow_in = !carry_in as u16;
+        let subtrahend = value as u16 + borrow_in;
+        let result = (self.register_a as u16).wrapping_sub(subtrahend) as u8;
+
+        self.status
+            .set(CpuFlags::CARRY, self.register_a as u16 >= subtrahend);
+        self.status.set(
+            CpuFlags::OVERFLOW,
+            (self.register_a ^ value) & (self.register_a ^ result) & 0x80 != 0,
+        );
         self.set_register_a(result);
     }
 
@@ -434,10 +612,16 @@ impl CPU {
 
     fn stack_push(&mut self, data: u8) {
         self.mem_write((STACK as u16) + self.stack_pointer as u16, data);
+        if self.stack_pointer == 0x00 {
+            self.stack_wrapped = self.debug_stack_guard;
+        }
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
     fn stack_pop(&mut self) -> u8 {
+        if self.stack_pointer == 0xff {
+            self.stack_wrapped = self.debug_stack_guard;
+        }
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.mem_read((STACK as u16) + self.stack_pointer as u16)
     }
@@ -488,6 +672,86 @@ impl CPU {
         self.set_register_a(data);
     }
 
+    /// Drives the IRQ input. The line is level-triggered: while it is held
+    /// high an interrupt is taken before every instruction that runs with
+    /// INTERRUPT_DISABLE clear, until the device releases it.
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        self.irq_line
+    }
+
+    /// When enabled, running stops with `StopReason::StackWrap` after any
+    /// instruction (or IRQ) that wraps the stack pointer past 0x00 or 0xff.
+    /// Off by default, as the hardware wraps silently.
+    pub fn set_debug_stack_guard(&mut self, enabled: bool) {
+        self.debug_stack_guard = enabled;
+    }
+
+    /// Defaults to `UnsupportedOpcodePolicy::Halt`. Only `step_info` ignores
+    /// it: having no `OpCode` to report, it always returns an error.
+    pub fn set_unsupported_opcode_policy(&mut self, policy: UnsupportedOpcodePolicy) {
+        self.unsupported_opcode_policy = policy;
+    }
+
+    pub fn instructions_retired(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    /// Number of times each opcode has been executed, indexed by opcode byte.
+    pub fn opcode_histogram(&self) -> &[u64; 256] {
+        &self.opcode_histogram
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.instructions_retired = 0;
+        self.opcode_histogram = [0; 256];
+    }
+
+    fn irq(&mut self) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status;
+        flags.remove(CpuFlags::BREAK);
+        flags.insert(CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+
+        self.program_counter = self.mem_read_u16(IRQ_VECTOR);
+    }
+
+    fn anc(&mut self, mode: &AddressingMode) {
+        self.and(mode);
+        self.status.set(CpuFlags::CARRY, self.register_a >> 7 == 1);
+    }
+
+    fn alr(&mut self, mode: &AddressingMode) {
+        self.and(mode);
+        self.lsr_accumulator();
+    }
+
+    /// AND then ROR A, except that C comes from bit 6 of the result and V from
+    /// bit 6 xor bit 5.
+    fn arr(&mut self, mode: &AddressingMode) {
+        self.and(mode);
+        self.ror_accumulator();
+        let result = self.register_a;
+        let bit_6 = (result >> 6) & 1;
+        let bit_5 = (result >> 5) & 1;
+        self.status.set(CpuFlags::CARRY, bit_6 == 1);
+        self.status.set(CpuFlags::OVERFLOW, bit_6 ^ bit_5 == 1);
+    }
+
+    fn axs(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let data = self.mem_read(addr);
+        let and = self.register_a & self.register_x;
+        self.status.set(CpuFlags::CARRY, data <= and);
+        self.register_x = and.wrapping_sub(data);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
         let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
@@ -500,9 +764,83 @@ impl CPU {
         self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x0600..(0x0600 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x0600);
+    /// The 2KB of internal work RAM at 0x0000-0x07ff.
+    pub fn work_ram(&self) -> &[u8] {
+        &self.memory[0..0x0800]
+    }
+
+    pub fn raw_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Appends a save state to `out`: a magic/version header followed by the
+    /// registers, status flags, IRQ line and the full 64K of memory.
+    pub fn serialize_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_VERSION);
+
+        out.push(self.register_a);
+        out.push(self.register_x);
+        out.push(self.register_y);
+        out.push(self.status.bits());
+        out.push(self.stack_pointer);
+        out.extend_from_slice(&self.program_counter.to_le_bytes());
+        out.push(self.irq_line as u8);
+        out.extend_from_slice(&self.memory);
+    }
+
+    /// Restores a state written by `serialize_state`. The buffer is fully
+    /// validated first, so on error the CPU is left untouched.
+    pub fn deserialize_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        if data.len() < STATE_HEADER_LEN {
+            return Err(StateError::Truncated {
+                expected: STATE_LEN,
+                actual: data.len(),
+            });
+        }
+        if &data[0..4] != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        if data[4] != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(data[4]));
+        }
+        if data.len() < STATE_LEN {
+            return Err(StateError::Truncated {
+                expected: STATE_LEN,
+                actual: data.len(),
+            });
+        }
+
+        let regs = &data[STATE_HEADER_LEN..STATE_HEADER_LEN + 8];
+        self.register_a = regs[0];
+        self.register_x = regs[1];
+        self.register_y = regs[2];
+        self.status = CpuFlags::from_bits_truncate(regs[3]);
+        self.stack_pointer = regs[4];
+        self.program_counter = u16::from_le_bytes([regs[5], regs[6]]);
+        self.irq_line = regs[7] != 0;
+        self.memory.copy_from_slice(&data[STATE_HEADER_LEN + 8..STATE_LEN]);
+        Ok(())
+    }
+
+    /// Copies `program` to 0x0600 and points the reset vector at it.
+    pub fn load(&mut self, program: Vec<u8>) -> Result<(), CpuLoadError> {
+        if program.len() > MAX_PROGRAM_LEN {
+            return Err(CpuLoadError::ProgramTooLarge {
+                len: program.len(),
+                max: MAX_PROGRAM_LEN,
+            });
+        }
+        self.load_unchecked(program);
+        Ok(())
+    }
+
+    /// Like `load`, but panics if the program does not fit in memory. A
+    /// program running into 0xFFFC has its reset vector overwritten.
+    pub fn load_unchecked(&mut self, program: Vec<u8>) {
+        let start = PROGRAM_START as usize;
+        self.memory[start..(start + program.len())].copy_from_slice(&program[..]);
+        self.mem_write_u16(0xFFFC, PROGRAM_START);
     }
 
     pub fn reset(&mut self) {
@@ -515,227 +853,444 @@ impl CPU {
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn run(&mut self) -> StopReason {
+        self.run_with_callback(|_| {})
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<StopReason, CpuLoadError> {
+        self.load(program)?;
         self.reset();
-        self.run();
+        Ok(self.run())
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    /// Runs until a `StopReason` occurs, invoking `callback` before each
+    /// instruction is fetched, so it observes the state the instruction is
+    /// about to execute against.
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> StopReason
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: &HashMap<u8, &'static opcodes::OpCode> = &(*opcodes::OPCODES_MAP);
-
         loop {
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+            self.poll_irq();
+            callback(self);
+            if let Some(reason) = self.execute_instruction() {
+                return reason;
+            }
+        }
+    }
 
-            let opcode = opcodes
-                .get(&code)
-                .unwrap();
+    /// Like `run`, but gives up after `max_instrs` instructions, or as soon as
+    /// `predicate` returns true before an instruction, so a runaway program
+    /// can't hang the caller. An unsupported opcode that halts execution is
+    /// returned as an error.
+    pub fn run_bounded<P>(
+        &mut self,
+        max_instrs: usize,
+        predicate: P,
+    ) -> Result<StopReason, CpuError>
+    where
+        P: Fn(&CPU) -> bool,
+    {
+        for _ in 0..max_instrs {
+            self.poll_irq();
+            if predicate(self) {
+                return Ok(StopReason::Predicate);
+            }
+            match self.execute_instruction() {
+                Some(StopReason::UnsupportedOpcode(err)) => return Err(err),
+                Some(reason) => return Ok(reason),
+                None => {}
+            }
+        }
+        Ok(StopReason::InstructionLimit)
+    }
+
+    /// Services a pending IRQ, if any, and executes a single instruction.
+    /// Returns the reason execution should stop, if there is one.
+    pub fn step(&mut self) -> Option<StopReason> {
+        self.poll_irq();
+        self.execute_instruction()
+    }
+
+    /// Like `step`, but reports what was executed, and returns an error
+    /// instead of panicking on an opcode the core does not implement.
+    pub fn step_info(&mut self) -> Result<ExecutedInstruction, CpuError> {
+        self.poll_irq();
+        self.execute_with_info()
+    }
+
+    /// `step_info` without servicing a pending IRQ first.
+    fn execute_with_info(&mut self) -> Result<ExecutedInstruction, CpuError> {
+        let pc = self.program_counter;
+        let code = self.mem_read(pc);
+        let opcode =
+            opcodes::opcode_info(code).ok_or(CpuError::UnsupportedOpcode { opcode: code, pc })?;
+        let mut cycles = opcode.cycles;
+        let mut operand_address = match opcode.mode {
+            AddressingMode::NoneAddressing => None,
+            _ => Some(self.get_absolute_address(&opcode.mode, pc.wrapping_add(1))),
+        };
+
+        if let Some(addr) = operand_address.filter(|_| opcode.has_page_cross_penalty()) {
+            let index = match opcode.mode {
+                AddressingMode::Absolute_X => self.register_x,
+                _ => self.register_y,
+            };
+            if page_crossed(addr.wrapping_sub(index as u16), addr) {
+                cycles += 1;
+            }
+        }
 
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
+        if let Some(taken) = self.branch_taken(code) {
+            let next = pc.wrapping_add(2);
+            let offset = self.mem_read(pc.wrapping_add(1)) as i8;
+            let target = next.wrapping_add(offset as u16);
+            if taken {
+                cycles += if page_crossed(next, target) { 2 } else { 1 };
+            }
+            operand_address = Some(target);
+        }
 
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+        let stop_reason = self.execute_instruction();
 
-                0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
-                0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
-                0xb8 => self.status.remove(CpuFlags::OVERFLOW),
-                0x18 => self.clear_carry_flag(),
-                0x38 => self.set_carry_flag(),
-                0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
-                0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
-
-                0xAA => self.tax(),
-                0xE8 => self.inx(),
-                0x00 => return,
-                0x48 => self.stack_push(self.register_a),
-                0x68 => {
-                    self.pla();
-                }
-                0x08 => {
-                    self.php();
-                }
-                0x28 => {
-                    self.plp();
-                }
-                0xea => {
-                    // do nothing
-                }
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
-                0x0a => self.asl_accumulator(),
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
-                0x4a => self.lsr_accumulator(),
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
-                0x2a => self.rol_accumulator(),
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
-                0x6a => self.ror_accumulator(),
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
-                0xc8 => self.iny(),
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
-                0xca => {
-                    self.dex();
-                }
-                0x88 => {
-                    self.dey();
-                }
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = indirect_ref;
-                }
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::BREAK2);
+        // JMP, JMP (indirect) and JSR: report where they went.
+        if let 0x4c | 0x6c | 0x20 = code {
+            operand_address = Some(self.program_counter);
+        }
 
-                    self.program_counter = self.stack_pop_u16();
-                }
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIV));
-                }
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIV));
-                }
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
-                0x86 | 0x96 | 0x8e => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
-                0x84 | 0x94 | 0x8c => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                }
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                _ => todo!(),
+        Ok(ExecutedInstruction {
+            opcode,
+            pc,
+            operand_address,
+            cycles,
+            stop_reason,
+        })
+    }
+
+    /// For a branch opcode, whether its condition currently holds.
+    fn branch_taken(&self, code: u8) -> Option<bool> {
+        let flag = |flag| self.status.contains(flag);
+        match code {
+            0x10 => Some(!flag(CpuFlags::NEGATIV)),
+            0x30 => Some(flag(CpuFlags::NEGATIV)),
+            0x50 => Some(!flag(CpuFlags::OVERFLOW)),
+            0x70 => Some(flag(CpuFlags::OVERFLOW)),
+            0x90 => Some(!flag(CpuFlags::CARRY)),
+            0xb0 => Some(flag(CpuFlags::CARRY)),
+            0xd0 => Some(!flag(CpuFlags::ZERO)),
+            0xf0 => Some(flag(CpuFlags::ZERO)),
+            _ => None,
+        }
+    }
+
+    /// Debugger step-over. A JSR is run through, for at most `max_instrs`
+    /// further instructions, until the subroutine returns to the instruction
+    /// after it; anything else executes like `step_info`. Stops early, with
+    /// the reason, if the subroutine hits a `StopReason` or the cap.
+    pub fn step_over(&mut self, max_instrs: usize) -> Result<Option<StopReason>, CpuError> {
+        // Take a pending IRQ first, so the JSR we wait on is the one that runs.
+        self.poll_irq();
+        let pc = self.program_counter;
+        let stack_pointer = self.stack_pointer;
+        let is_jsr = self.mem_read(pc) == 0x20;
+        match self.execute_instruction() {
+            Some(StopReason::UnsupportedOpcode(err)) => return Err(err),
+            Some(reason) => return Ok(Some(reason)),
+            None if !is_jsr => return Ok(None),
+            None => {}
+        }
+
+        // Also match the stack depth, so a recursive call that passes through
+        // the same return address doesn't end the step early.
+        let return_address = pc.wrapping_add(3);
+        let returned =
+            |cpu: &CPU| cpu.program_counter == return_address && cpu.stack_pointer == stack_pointer;
+        match self.run_bounded(max_instrs, returned)? {
+            StopReason::Predicate => Ok(None),
+            reason => Ok(Some(reason)),
+        }
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status.bits(),
+            stack_pointer: self.stack_pointer,
+            program_counter: self.program_counter,
+        }
+    }
+
+    pub fn trace_state(&self) -> TraceState {
+        TraceState {
+            registers: self.cpu_state(),
+            opcode: self.mem_read(self.program_counter),
+            instructions_retired: self.instructions_retired,
+        }
+    }
+
+    /// Steps up to `max_instructions` (stopping early on a `StopReason` or an
+    /// unsupported opcode) and folds the `TraceState` before each one into a
+    /// 64-bit FNV-1a digest. Any change in behaviour changes the digest.
+    pub fn run_digest(&mut self, max_instructions: usize) -> u64 {
+        let mut digest = FNV_OFFSET_BASIS;
+        for _ in 0..max_instructions {
+            self.poll_irq();
+            digest = self.trace_state().fold_into(digest);
+            if self.execute_instruction().is_some() {
+                break;
             }
+        }
+        digest
+    }
+
+    fn poll_irq(&mut self) {
+        if self.irq_line && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            self.irq();
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Option<StopReason> {
+        let reason = self.execute_opcode();
+        // Checked on every exit, and after the opcode, so a wrap from an IRQ
+        // taken just before it is reported now rather than on a later run.
+        // It wins over the opcode's own reason, being the earlier event.
+        if self.stack_wrapped {
+            self.stack_wrapped = false;
+            return Some(StopReason::StackWrap);
+        }
+        reason
+    }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    fn execute_opcode(&mut self) -> Option<StopReason> {
+        let ref opcodes: &HashMap<u8, &'static opcodes::OpCode> = &(*opcodes::OPCODES_MAP);
+
+        let code = self.mem_read(self.program_counter);
+        let opcode = match (opcodes.get(&code), self.unsupported_opcode_policy) {
+            (Some(opcode), _) => opcode,
+            (None, UnsupportedOpcodePolicy::Halt) => {
+                return Some(StopReason::UnsupportedOpcode(CpuError::UnsupportedOpcode {
+                    opcode: code,
+                    pc: self.program_counter,
+                }));
             }
+            (None, UnsupportedOpcodePolicy::SkipAsNop) => {
+                // retire it as a one-byte NOP
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.instructions_retired += 1;
+                self.opcode_histogram[code as usize] += 1;
+                return None;
+            }
+        };
 
-            callback(self);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let program_counter_state = self.program_counter;
+        self.instructions_retired += 1;
+        self.opcode_histogram[code as usize] += 1;
+
+        match code {
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
+
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
+
+            0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+            0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+            0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+            0x18 => self.clear_carry_flag(),
+            0x38 => self.set_carry_flag(),
+            0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+
+            0xAA => self.tax(),
+            0xE8 => self.inx(),
+            0x00 => return Some(StopReason::Break),
+            0x48 => self.stack_push(self.register_a),
+            0x68 => {
+                self.pla();
+            }
+            0x08 => {
+                self.php();
+            }
+            0x28 => {
+                self.plp();
+            }
+            0xea => {
+                // do nothing
+            }
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
+            0x0a => self.asl_accumulator(),
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
+            0x4a => self.lsr_accumulator(),
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
+            0x2a => self.rol_accumulator(),
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
+            0x6a => self.ror_accumulator(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
+            0xc8 => self.iny(),
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
+            0xca => {
+                self.dex();
+            }
+            0x88 => {
+                self.dey();
+            }
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            }
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+            0x4c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.program_counter = indirect_ref;
+            }
+            0x20 => {
+                self.stack_push_u16(self.program_counter.wrapping_add(1));
+                let target_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = target_address
+            }
+            0x60 => {
+                self.program_counter = self.stack_pop_u16().wrapping_add(1);
+            }
+            0x40 => {
+                self.status.bits = self.stack_pop();
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::BREAK2);
+
+                self.program_counter = self.stack_pop_u16();
+            }
+            0xd0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIV));
+            }
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIV));
+            }
+            0xf0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
+            0xb0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            }
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            }
+            0x86 | 0x96 | 0x8e => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            }
+            0x84 | 0x94 | 0x8c => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            }
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            0x8a => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            0x9a => {
+                self.stack_pointer = self.register_x;
+            }
+            0x98 => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            /* unofficial */
+            0x0b | 0x2b => self.anc(&opcode.mode),
+            0x4b => self.alr(&opcode.mode),
+            0x6b => self.arr(&opcode.mode),
+            0xcb => self.axs(&opcode.mode),
+            // every opcode in the table has an arm; others returned above
+            _ => unreachable!("opcode {:#04x} is in the table but not implemented", code),
+        }
+
+        if program_counter_state == self.program_counter {
+            self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
         }
+        None
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::Asm;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x05, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 5);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
         assert!(cpu.status.bits() & 0b1000_0000 == 0);
@@ -744,40 +1299,99 @@ mod test {
     #[test]
     fn test_0xa9_lda_zero_flag() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x00, 0x00]).unwrap();
         assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
     }
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0x0a, 0xaa, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x0a, 0xaa, 0x00]).unwrap();
 
         assert_eq!(cpu.register_x, 10)
     }
 
+    // clc; lda #$40; adc #$40; sec -> A = $80 with carry and overflow set
+    const SET_CARRY_AND_OVERFLOW: [u8; 6] = [0x18, 0xa9, 0x40, 0x69, 0x40, 0x38];
+
+    fn assert_carry_and_overflow_kept(cpu: &CPU) {
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
     #[test]
-    fn test_5_ops_working_together() {
+    fn test_0xa8_tay_flags() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        // lda #$00; ldy #$01; tay; brk
+        cpu.load_and_run(vec![0xa9, 0x00, 0xa0, 0x01, 0xa8, 0x00]).unwrap();
+        assert_eq!(cpu.register_y, 0x00);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
 
-        assert_eq!(cpu.register_x, 0xc1);
+        // ldy #$01; tay; brk
+        let mut program = SET_CARRY_AND_OVERFLOW.to_vec();
+        program.extend_from_slice(&[0xa0, 0x01, 0xa8, 0x00]);
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.register_y, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+        assert_carry_and_overflow_kept(&cpu);
     }
 
     #[test]
-    fn test_inx_overflow() {
+    fn test_0x98_tya_flags() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0xff, 0xaa, 0xe8, 0xe8, 0x00]);
+        // ldy #$00; lda #$01; tya; brk
+        cpu.load_and_run(vec![0xa0, 0x00, 0xa9, 0x01, 0x98, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
 
-        assert_eq!(cpu.register_x, 1);
+        // ldy #$80; lda #$01; tya; brk
+        let mut program = SET_CARRY_AND_OVERFLOW.to_vec();
+        program.extend_from_slice(&[0xa0, 0x80, 0xa9, 0x01, 0x98, 0x00]);
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+        assert_carry_and_overflow_kept(&cpu);
     }
 
     #[test]
-    fn test_lda_from_memory() {
+    fn test_0x8a_txa_flags() {
+        let mut cpu = CPU::new();
+        // ldx #$00; lda #$01; txa; brk
+        cpu.load_and_run(vec![0xa2, 0x00, 0xa9, 0x01, 0x8a, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+
+        // ldx #$80; lda #$01; txa; brk
+        let mut program = SET_CARRY_AND_OVERFLOW.to_vec();
+        program.extend_from_slice(&[0xa2, 0x80, 0xa9, 0x01, 0x8a, 0x00]);
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+        assert_carry_and_overflow_kept(&cpu);
+    }
+
+    #[test]
+    fn test_5_ops_working_together() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 0xc1);
+    }
+
+    #[test]
+    fn test_inx_overflow() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0xff, 0xaa, 0xe8, 0xe8, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_lda_from_memory() {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0x55);
 
-        cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xa5, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 0x55);
     }
 
@@ -786,7 +1400,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0x55);
 
-        cpu.load_and_run(vec![0xa2, 0x10, 0xa6, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xa2, 0x10, 0xa6, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.register_x, 0x55);
     }
 
@@ -795,7 +1409,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0x55);
 
-        cpu.load_and_run(vec![0xa0, 0x10, 0xa4, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xa0, 0x10, 0xa4, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.register_y, 0x55);
     }
 
@@ -803,7 +1417,7 @@ mod test {
     fn test_sta() {
         let mut cpu = CPU::new();
         cpu.register_a = 0x55;
-        cpu.load_and_run(vec![0xa9, 0x55, 0x85, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x55, 0x85, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.mem_read(0x10), 0x55);
     }
 
@@ -812,7 +1426,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.register_a = 0x55;
         cpu.register_x = 0x05;
-        cpu.load_and_run(vec![0xa9, 0x55, 0x95, 0x05, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x55, 0x95, 0x05, 0x00]).unwrap();
         assert_eq!(cpu.mem_read(0x05), 0x55);
     }
 
@@ -820,10 +1434,101 @@ mod test {
     fn test_sta_absolute() {
         let mut cpu = CPU::new();
         cpu.register_a = 0x55;
-        cpu.load_and_run(vec![0xa9, 0x55, 0x8d, 0x00, 0x80, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x55, 0x8d, 0x00, 0x80, 0x00]).unwrap();
         assert_eq!(cpu.mem_read(0x8000), 0x55);
     }
 
+    #[test]
+    fn test_zero_page_x_wraps_within_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x01, 0x11);
+        cpu.mem_write(0x0101, 0x22);
+        // ldx #$02; lda $ff,x
+        cpu.load_and_run(vec![0xa2, 0x02, 0xb5, 0xff, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x11);
+    }
+
+    #[test]
+    fn test_zero_page_y_wraps_within_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x01, 0x11);
+        cpu.mem_write(0x0101, 0x22);
+        // ldy #$02; ldx $ff,y
+        cpu.load_and_run(vec![0xa0, 0x02, 0xb6, 0xff, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0x11);
+    }
+
+    #[test]
+    fn test_indirect_x_pointer_wraps_within_zero_page() {
+        let mut cpu = CPU::new();
+        // pointer at $ff/$00 -> $1234, not $ff/$100
+        cpu.mem_write(0xff, 0x34);
+        cpu.mem_write(0x00, 0x12);
+        cpu.mem_write(0x0100, 0x56);
+        cpu.mem_write(0x1234, 0x77);
+        cpu.mem_write(0x5634, 0x88);
+        // ldx #$01; lda ($fe,x)
+        cpu.load_and_run(vec![0xa2, 0x01, 0xa1, 0xfe, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn test_indirect_y_pointer_wraps_within_zero_page() {
+        let mut cpu = CPU::new();
+        // pointer at $ff/$00 -> $12f0, not $ff/$100
+        cpu.mem_write(0xff, 0xf0);
+        cpu.mem_write(0x00, 0x12);
+        cpu.mem_write(0x0100, 0x56);
+        cpu.mem_write(0x1310, 0x77);
+        cpu.mem_write(0x5710, 0x88);
+        // ldy #$20; lda ($ff),y -> $12f0 + $20 crosses into $13xx
+        cpu.load_and_run(vec![0xa0, 0x20, 0xb1, 0xff, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn test_indirect_x_index_wraps_within_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x00);
+        cpu.mem_write(0x11, 0x20);
+        cpu.mem_write(0x2000, 0x99);
+        // ldx #$20; lda ($f0,x) -> pointer at $10, not $110
+        cpu.load_and_run(vec![0xa2, 0x20, 0xa1, 0xf0, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x99);
+    }
+
+    #[test]
+    fn test_txs_does_not_affect_flags() {
+        let mut cpu = CPU::new();
+        // ldx #$00; lda #$01; txs; brk
+        cpu.load_and_run(vec![0xa2, 0x00, 0xa9, 0x01, 0x9a, 0x00]).unwrap();
+        assert_eq!(cpu.stack_pointer, 0x00);
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_tsx_sets_flags() {
+        let mut cpu = CPU::new();
+        // ldx #$00; txs; ldx #$01; tsx; brk
+        cpu.load_and_run(vec![0xa2, 0x00, 0x9a, 0xa2, 0x01, 0xba, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0x00);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_pc_wraps_past_top_of_memory() {
+        let mut cpu = CPU::new();
+        // $fffd: jsr $0700 (high byte of the target sits at $ffff)
+        cpu.memory[0xfffd..=0xffff].copy_from_slice(&[0x20, 0x00, 0x07]);
+        // $0700: rts; $0000: brk
+        cpu.mem_write(0x0700, 0x60);
+        cpu.program_counter = 0xfffd;
+
+        assert_eq!(cpu.run(), StopReason::Break);
+        assert_eq!(cpu.program_counter, 0x0001);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
     #[test]
     fn test_stack_push_and_pop_u16() {
         let mut cpu = CPU::new();
@@ -862,11 +1567,71 @@ mod test {
         assert_eq!(cpu.memory[0x11], 0x66);
     }
 
+    #[test]
+    fn test_work_ram() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x55, 0x85, 0x10, 0x00]).unwrap();
+
+        assert_eq!(cpu.work_ram().len(), 0x0800);
+        assert_eq!(cpu.work_ram()[0x10], 0x55);
+        assert_eq!(cpu.raw_memory().len(), 0x10000);
+        assert_eq!(cpu.raw_memory()[0x0600], 0xa9);
+    }
+
+    #[test]
+    fn test_load_rejects_oversized_program() {
+        let mut cpu = CPU::new();
+        let program = vec![0xea; 0xfffc - 0x0600 + 1];
+        assert_eq!(
+            cpu.load(program),
+            Err(CpuLoadError::ProgramTooLarge {
+                len: 0xf9fd,
+                max: 0xf9fc
+            })
+        );
+        assert_eq!(cpu.mem_read(0x0600), 0x00);
+    }
+
+    #[test]
+    fn test_load_and_run_rejects_oversized_program() {
+        let mut cpu = CPU::new();
+        let program = vec![0xea; 0xfffc - 0x0600 + 1];
+        assert!(matches!(
+            cpu.load_and_run(program),
+            Err(CpuLoadError::ProgramTooLarge { .. })
+        ));
+        assert_eq!(cpu.instructions_retired(), 0);
+    }
+
+    #[test]
+    fn test_load_accepts_program_up_to_reset_vector() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load(vec![0xea; 0xfffc - 0x0600]), Ok(()));
+        assert_eq!(cpu.mem_read(0xfffb), 0xea);
+        assert_eq!(cpu.mem_read_u16(0xFFFC), 0x0600);
+    }
+
+    #[test]
+    fn test_clone_is_independent() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x55, 0x85, 0x10, 0x00]).unwrap();
+
+        let mut clone = cpu.clone();
+        clone.mem_write(0x10, 0x66);
+        clone.register_a = 0x01;
+        clone.set_irq_line(true);
+
+        assert_eq!(cpu.mem_read(0x10), 0x55);
+        assert_eq!(cpu.register_a, 0x55);
+        assert!(!cpu.irq_asserted());
+        assert_eq!(clone.mem_read(0x10), 0x66);
+    }
+
     #[test]
     fn test_and_immediate() {
         let mut cpu = CPU::new();
         cpu.register_a = 0b1100_0000;
-        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x29, 0b0101_0101, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x29, 0b0101_0101, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 0b0000_0000);
     }
 
@@ -874,7 +1639,7 @@ mod test {
     fn test_eor_immediate() {
         let mut cpu = CPU::new();
         cpu.register_a = 0b1100_0000;
-        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x49, 0b0101_0101, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x49, 0b0101_0101, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 0b1111_1111);
     }
 
@@ -882,7 +1647,7 @@ mod test {
     fn test_ora_immediate() {
         let mut cpu = CPU::new();
         cpu.register_a = 0b1100_0000;
-        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x09, 0b0101_0101, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0b1010_1010, 0x09, 0b0101_0101, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 0b1111_1111);
     }
 
@@ -890,7 +1655,7 @@ mod test {
     fn test_asl_accumulator() {
         let mut cpu = CPU::new();
         cpu.register_a = 0b1000_0000;
-        cpu.load_and_run(vec![0x0a, 0x00]);
+        cpu.load_and_run(vec![0x0a, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 0b0000_0000);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
         assert!(cpu.status.bits() & 0b0000_0001 == 0b00);
@@ -900,7 +1665,7 @@ mod test {
     fn test_asl_zero_page() {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0b1000_0001);
-        cpu.load_and_run(vec![0x06, 0x10, 0x00]);
+        cpu.load_and_run(vec![0x06, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.mem_read(0x10), 0b0000_0010);
         assert!(!cpu.status.contains(CpuFlags::ZERO));
         assert!(cpu.status.contains(CpuFlags::CARRY));
@@ -967,7 +1732,7 @@ mod test {
     fn test_dec() {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0x55);
-        cpu.load_and_run(vec![0xc6, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xc6, 0x10, 0x00]).unwrap();
         assert_eq!(cpu.mem_read(0x10), 0x54);
     }
 
@@ -976,9 +1741,869 @@ mod test {
         let mut cpu = CPU::new();
         cpu.register_a = 0b1100_0000;
         cpu.mem_write(0x10, 0b1010_1010);
-        cpu.load_and_run(vec![0x24, 0x10, 0x00]);
+        cpu.load_and_run(vec![0x24, 0x10, 0x00]).unwrap();
         assert!(cpu.status.contains(CpuFlags::ZERO));
         assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
         assert!(cpu.status.contains(CpuFlags::NEGATIV));
     }
+
+    #[test]
+    fn test_adc_overflow_positive_plus_positive() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0x50, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0xa0);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+    }
+
+    #[test]
+    fn test_adc_no_overflow_positive_plus_negative() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0xd0, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x20);
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_adc_overflow_negative_plus_negative() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0xd0, 0x69, 0x90, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x60);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_adc_overflow_from_carry_in() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0x7f, 0x69, 0x00, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_adc_carry_in_wraps_to_zero() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0xff, 0x69, 0x00, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_sbc_overflow_positive_minus_negative() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0x50, 0xe9, 0xb0, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0xa0);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_no_overflow_positive_minus_negative() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0x50, 0xe9, 0xf0, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x60);
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_overflow_negative_minus_positive() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0xd0, 0xe9, 0x70, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x60);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_with_borrow_in() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x18, 0xa9, 0x50, 0xe9, 0x10, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x3f);
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_borrow_out_and_zero() {
+        let mut cpu = CPU::new();
+        // sec; lda #$10; sbc #$20; brk
+        cpu.load_and_run(vec![0x38, 0xa9, 0x10, 0xe9, 0x20, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0xf0);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+
+        // sec; lda #$20; sbc #$20; brk
+        cpu.load_and_run(vec![0x38, 0xa9, 0x20, 0xe9, 0x20, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_sbc_matches_adc_of_complement() {
+        // binary SBC is ADC of the one's complement, for every input
+        for a in 0..=0xff_u8 {
+            for value in 0..=0xff_u8 {
+                for carry_in in [false, true] {
+                    let mut sbc = CPU::new();
+                    sbc.register_a = a;
+                    sbc.sbc_inner(value, carry_in);
+
+                    let mut adc = CPU::new();
+                    adc.register_a = a;
+                    adc.adc_inner(!value, carry_in);
+
+                    assert_eq!(
+                        (sbc.register_a, sbc.status),
+                        (adc.register_a, adc.status),
+                        "a={:#04x} value={:#04x} carry_in={}",
+                        a,
+                        value,
+                        carry_in
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_irq_line_fires_until_released() {
+        let mut cpu = CPU::new();
+        // cli; nop; nop; brk
+        cpu.load(vec![0x58, 0xea, 0xea, 0x00]).unwrap();
+        // handler: inc $10; rti
+        cpu.memory[0x0700..0x0703].copy_from_slice(&[0xe6, 0x10, 0x40]);
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        cpu.reset();
+
+        cpu.set_irq_line(true);
+        cpu.run_with_callback(|cpu| {
+            if cpu.mem_read(0x10) == 3 {
+                cpu.set_irq_line(false);
+            }
+        });
+
+        assert_eq!(cpu.mem_read(0x10), 3);
+        assert!(!cpu.irq_asserted());
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_irq_rti_round_trip_restores_flags() {
+        let mut cpu = CPU::new();
+        // lda #$c9; pha; plp (N V D C, Z and I clear); nop; brk
+        cpu.load(vec![0xa9, 0xc9, 0x48, 0x28, 0xea, 0x00]).unwrap();
+        // handler: lda #$00 (clobbers Z and N); rti
+        cpu.memory[0x0700..0x0703].copy_from_slice(&[0xa9, 0x00, 0x40]);
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        cpu.reset();
+
+        cpu.set_irq_line(true);
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter == 0x0700 {
+                cpu.set_irq_line(false);
+            }
+        });
+
+        // pushed frame has B clear and bit 5 set
+        assert_eq!(cpu.mem_read(STACK + STACK_RESET as u16 - 2), 0b1110_1001);
+        assert_eq!(cpu.status.bits(), 0b1110_1001);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_irq_line_ignored_while_interrupts_disabled() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xea, 0xea, 0x00]).unwrap();
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        cpu.reset();
+
+        cpu.set_irq_line(true);
+        cpu.run();
+
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert!(cpu.irq_asserted());
+    }
+
+    #[test]
+    fn test_php_plp_break_flag() {
+        let mut cpu = CPU::new();
+        // sec; php; pla; sta $10; lda #$10; pha; plp; brk
+        cpu.load_and_run(vec![
+            0x38, 0x08, 0x68, 0x85, 0x10, 0xa9, 0x10, 0x48, 0x28, 0x00,
+        ]).unwrap();
+
+        // PHP pushes B and bit 5 set.
+        assert_eq!(cpu.mem_read(0x10), 0b0011_0101);
+        // PLP drops B and forces bit 5.
+        assert_eq!(cpu.status.bits(), 0b0010_0000);
+    }
+
+    #[test]
+    fn test_rti_break_flag() {
+        let mut cpu = CPU::new();
+        // push a frame returning to $0700 with status $df (B set, bit 5 clear)
+        // lda #$07; pha; lda #$00; pha; lda #$df; pha; rti
+        cpu.load_and_run(vec![
+            0xa9, 0x07, 0x48, 0xa9, 0x00, 0x48, 0xa9, 0xdf, 0x48, 0x40,
+        ]).unwrap();
+
+        assert_eq!(cpu.status.bits(), 0b1110_1111);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        // halted on the BRK at $0700
+        assert_eq!(cpu.program_counter, 0x0701);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![
+            // write "lda #$42; rts" to $0080
+            0xa9, 0xa9, 0x85, 0x80, 0xa9, 0x42, 0x85, 0x81, 0xa9, 0x60, 0x85, 0x82,
+            // jsr $0080; sta $10
+            0x20, 0x80, 0x00, 0x85, 0x10,
+            // patch the operand; jsr $0080; sta $11; brk
+            0xa9, 0x43, 0x85, 0x81, 0x20, 0x80, 0x00, 0x85, 0x11, 0x00,
+        ]).unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.mem_read(0x11), 0x43);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_unsupported_opcode_policy() {
+        // lda #$01; .byte $02; ldx #$02; brk
+        let program = vec![0xa9, 0x01, 0x02, 0xa2, 0x02, 0x00];
+
+        let mut cpu = CPU::new();
+        cpu.load(program.clone()).unwrap();
+        cpu.reset();
+        assert_eq!(
+            cpu.run(),
+            StopReason::UnsupportedOpcode(CpuError::UnsupportedOpcode {
+                opcode: 0x02,
+                pc: 0x0602,
+            })
+        );
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.register_x, 0);
+
+        let mut cpu = CPU::new();
+        cpu.set_unsupported_opcode_policy(UnsupportedOpcodePolicy::SkipAsNop);
+        assert_eq!(cpu.load_and_run(program), Ok(StopReason::Break));
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.register_x, 0x02);
+        assert_eq!(cpu.instructions_retired(), 4);
+    }
+
+    #[test]
+    fn test_step_over_and_run_digest_follow_unsupported_opcode_policy() {
+        // .byte $02; inx; brk
+        let program = vec![0x02, 0xe8, 0x00];
+
+        let mut cpu = CPU::new();
+        cpu.load(program.clone()).unwrap();
+        cpu.reset();
+        assert_eq!(
+            cpu.step_over(10),
+            Err(CpuError::UnsupportedOpcode {
+                opcode: 0x02,
+                pc: 0x0600,
+            })
+        );
+        cpu.run_digest(10);
+        assert_eq!(cpu.program_counter, 0x0600);
+
+        let mut cpu = CPU::new();
+        cpu.set_unsupported_opcode_policy(UnsupportedOpcodePolicy::SkipAsNop);
+        cpu.load(program.clone()).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.step_over(10), Ok(None));
+        assert_eq!(cpu.program_counter, 0x0601);
+
+        cpu.reset();
+        cpu.run_digest(10);
+        assert_eq!(cpu.register_x, 0x01);
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_inc_absolute_x_page_cross_cycles() {
+        let mut cpu = CPU::new();
+        // ldx #$20; inc $10f0,x; brk
+        cpu.load(vec![0xa2, 0x20, 0xfe, 0xf0, 0x10, 0x00]).unwrap();
+        cpu.reset();
+        cpu.mem_write(0x1110, 0x41);
+
+        cpu.step_info().unwrap();
+        let inc = cpu.step_info().unwrap();
+        assert_eq!(inc.operand_address, Some(0x1110));
+        assert_eq!(inc.cycles, 7);
+        assert_eq!(cpu.mem_read(0x1110), 0x42);
+    }
+
+    #[test]
+    fn test_run_bounded_stops_at_instruction_limit() {
+        let mut cpu = CPU::new();
+        cpu.load(Asm::new().label("loop").jmp("loop").build())
+            .unwrap();
+        cpu.reset();
+
+        assert_eq!(
+            cpu.run_bounded(100, |_| false),
+            Ok(StopReason::InstructionLimit)
+        );
+        assert_eq!(cpu.instructions_retired(), 100);
+        assert_eq!(cpu.program_counter, 0x0600);
+    }
+
+    #[test]
+    fn test_run_bounded_stops_on_predicate_brk_and_error() {
+        let mut cpu = CPU::new();
+        cpu.load(Asm::new().label("loop").inx().jmp("loop").build())
+            .unwrap();
+        cpu.reset();
+        assert_eq!(
+            cpu.run_bounded(100, |cpu| cpu.register_x == 5),
+            Ok(StopReason::Predicate)
+        );
+        assert_eq!(cpu.register_x, 5);
+
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0x00]).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.run_bounded(100, |_| false), Ok(StopReason::Break));
+
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0x02]).unwrap();
+        cpu.reset();
+        assert_eq!(
+            cpu.run_bounded(100, |_| false),
+            Err(CpuError::UnsupportedOpcode {
+                opcode: 0x02,
+                pc: 0x0601,
+            })
+        );
+    }
+
+    #[test]
+    fn test_every_table_opcode_is_implemented() {
+        for op in opcodes::CPU_OPS_CODES.iter() {
+            let mut cpu = CPU::new();
+            cpu.load(vec![op.code, 0x00, 0x00]).unwrap();
+            cpu.reset();
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn test_instructions_retired() {
+        let mut cpu = CPU::new();
+        let program = Asm::new()
+            .ldx_imm(0x03)
+            .label("loop")
+            .dex()
+            .bne("loop")
+            .brk()
+            .build();
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.instructions_retired(), 8);
+        assert_eq!(cpu.opcode_histogram()[0xca], 3);
+        assert_eq!(cpu.opcode_histogram()[0xd0], 3);
+        assert_eq!(cpu.opcode_histogram()[0xa2], 1);
+        assert_eq!(cpu.opcode_histogram()[0x00], 1);
+
+        cpu.reset_stats();
+        assert_eq!(cpu.instructions_retired(), 0);
+        assert_eq!(cpu.opcode_histogram()[0xca], 0);
+    }
+
+    #[test]
+    fn test_callback_runs_before_each_instruction() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xa9, 0x05, 0xaa, 0x00]).unwrap();
+        cpu.reset();
+
+        let mut seen = vec![];
+        cpu.run_with_callback(|cpu| {
+            seen.push((cpu.program_counter, cpu.register_a, cpu.register_x));
+        });
+
+        assert_eq!(
+            seen,
+            vec![
+                (0x0600, 0x00, 0x00),
+                (0x0602, 0x05, 0x00),
+                (0x0603, 0x05, 0x05)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack_guard_reports_overflow() {
+        let mut cpu = CPU::new();
+        // ldx #$00; txs; pha; pha; brk
+        cpu.load(vec![0xa2, 0x00, 0x9a, 0x48, 0x48, 0x00]).unwrap();
+        cpu.reset();
+        cpu.set_debug_stack_guard(true);
+
+        assert_eq!(cpu.run(), StopReason::StackWrap);
+        assert_eq!(cpu.stack_pointer, 0xff);
+        assert_eq!(cpu.program_counter, 0x0604);
+
+        assert_eq!(cpu.run(), StopReason::Break);
+        assert_eq!(cpu.stack_pointer, 0xfe);
+    }
+
+    #[test]
+    fn test_stack_guard_reports_underflow() {
+        let mut cpu = CPU::new();
+        // ldx #$ff; txs; pla; brk
+        cpu.load(vec![0xa2, 0xff, 0x9a, 0x68, 0x00]).unwrap();
+        cpu.reset();
+        cpu.set_debug_stack_guard(true);
+
+        assert_eq!(cpu.run(), StopReason::StackWrap);
+        assert_eq!(cpu.stack_pointer, 0x00);
+    }
+
+    #[test]
+    fn test_stack_guard_reports_wrap_from_irq() {
+        let mut cpu = CPU::new();
+        // the handler at $0700 is brk; brk
+        cpu.load(vec![0xea]).unwrap();
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        cpu.reset();
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+        cpu.stack_pointer = 0x01;
+        cpu.set_debug_stack_guard(true);
+        cpu.set_irq_line(true);
+
+        assert_eq!(cpu.run(), StopReason::StackWrap);
+        assert_eq!(cpu.stack_pointer, 0xfe);
+        assert_eq!(cpu.program_counter, 0x0701);
+
+        assert_eq!(cpu.run(), StopReason::Break);
+        assert_eq!(cpu.run(), StopReason::Break);
+    }
+
+    #[test]
+    fn test_stack_wraps_silently_without_guard() {
+        let mut cpu = CPU::new();
+        let reason = cpu.load_and_run(vec![0xa2, 0x00, 0x9a, 0x48, 0x48, 0x00]).unwrap();
+
+        assert_eq!(reason, StopReason::Break);
+        assert_eq!(cpu.stack_pointer, 0xfe);
+    }
+
+    #[test]
+    fn test_step_info() {
+        let mut cpu = CPU::new();
+        // ldx #$02; lda $10,x; sta $0200; brk
+        cpu.load(vec![0xa2, 0x02, 0xb5, 0x10, 0x8d, 0x00, 0x02, 0x00]).unwrap();
+        cpu.reset();
+        cpu.mem_write(0x12, 0x77);
+
+        let ldx = cpu.step_info().unwrap();
+        assert_eq!(ldx.opcode.mnemonic, "LDX");
+        assert_eq!(ldx.pc, 0x0600);
+        assert_eq!(ldx.operand_address, Some(0x0601));
+        assert_eq!(ldx.cycles, 2);
+        assert_eq!(ldx.stop_reason, None);
+
+        let lda = cpu.step_info().unwrap();
+        assert_eq!(lda.opcode.mnemonic, "LDA");
+        assert_eq!(lda.pc, 0x0602);
+        assert_eq!(lda.operand_address, Some(0x12));
+        assert_eq!(lda.cycles, 4);
+        assert_eq!(cpu.register_a, 0x77);
+
+        let sta = cpu.step_info().unwrap();
+        assert_eq!(sta.opcode.mnemonic, "STA");
+        assert_eq!(sta.pc, 0x0604);
+        assert_eq!(sta.operand_address, Some(0x0200));
+        assert_eq!(sta.cycles, 4);
+
+        let brk = cpu.step_info().unwrap();
+        assert_eq!(brk.opcode.mnemonic, "BRK");
+        assert_eq!(brk.operand_address, None);
+        assert_eq!(brk.stop_reason, Some(StopReason::Break));
+    }
+
+    #[test]
+    fn test_step_info_page_cross_cycles() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![
+            0xa2, 0x20, // ldx #$20
+            0xbd, 0x00, 0x10, // lda $1000,x
+            0xbd, 0xf0, 0x10, // lda $10f0,x
+            0x9d, 0xf0, 0x10, // sta $10f0,x
+            0xa0, 0x20, // ldy #$20
+            0xb1, 0xff, // lda ($ff),y
+        ])
+        .unwrap();
+        cpu.mem_write(0xff, 0xf0);
+        cpu.mem_write(0x00, 0x12);
+        cpu.reset();
+
+        cpu.step_info().unwrap();
+        assert_eq!(cpu.step_info().unwrap().cycles, 4);
+        let crossed = cpu.step_info().unwrap();
+        assert_eq!(crossed.operand_address, Some(0x1110));
+        assert_eq!(crossed.cycles, 5);
+        // stores always take the extra cycle, so no penalty on top
+        assert_eq!(cpu.step_info().unwrap().cycles, 5);
+        cpu.step_info().unwrap();
+        let indirect = cpu.step_info().unwrap();
+        assert_eq!(indirect.operand_address, Some(0x1310));
+        assert_eq!(indirect.cycles, 6);
+    }
+
+    #[test]
+    fn test_step_info_jump_targets() {
+        let mut cpu = CPU::new();
+        // $0600: jmp $0610
+        cpu.load(vec![0x4c, 0x10, 0x06]).unwrap();
+        // $0610: jsr $0620
+        cpu.memory[0x0610..0x0613].copy_from_slice(&[0x20, 0x20, 0x06]);
+        // $0620: jmp ($0030)
+        cpu.memory[0x0620..0x0623].copy_from_slice(&[0x6c, 0x30, 0x00]);
+        cpu.mem_write_u16(0x0030, 0x0640);
+        cpu.reset();
+
+        assert_eq!(cpu.step_info().unwrap().operand_address, Some(0x0610));
+        assert_eq!(cpu.step_info().unwrap().operand_address, Some(0x0620));
+        assert_eq!(cpu.step_info().unwrap().operand_address, Some(0x0640));
+    }
+
+    #[test]
+    fn test_step_info_unsupported_opcode() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xea, 0x02, 0x00]).unwrap();
+        cpu.reset();
+
+        assert!(cpu.step_info().is_ok());
+        assert_eq!(
+            cpu.step_info().unwrap_err(),
+            CpuError::UnsupportedOpcode {
+                opcode: 0x02,
+                pc: 0x0601
+            }
+        );
+        assert_eq!(cpu.program_counter, 0x0601);
+    }
+
+    #[test]
+    fn test_step_over_jsr() {
+        let mut cpu = CPU::new();
+        let program = Asm::new()
+            .jsr("sub")
+            .sta_zp(0x10)
+            .brk()
+            .label("sub")
+            .lda_imm(0x42)
+            .inx()
+            .rts()
+            .build();
+        cpu.load(program).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.step_over(100), Ok(None));
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x01);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+
+        // not a JSR: a single step
+        assert_eq!(cpu.step_over(100), Ok(None));
+        assert_eq!(cpu.program_counter, 0x0605);
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.step_over(100), Ok(Some(StopReason::Break)));
+    }
+
+    #[test]
+    fn test_step_over_stops_on_brk_in_subroutine() {
+        let mut cpu = CPU::new();
+        let program = Asm::new().jsr("sub").brk().label("sub").inx().brk();
+        cpu.load(program.build()).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.step_over(100), Ok(Some(StopReason::Break)));
+        assert_eq!(cpu.register_x, 0x01);
+    }
+
+    #[test]
+    fn test_step_over_jsr_in_pending_irq_handler() {
+        let mut cpu = CPU::new();
+        // cli; nop; brk
+        cpu.load(vec![0x58, 0xea, 0x00]).unwrap();
+        // handler: jsr $0710; rti; sub: inx; rts
+        cpu.memory[0x0700..0x0704].copy_from_slice(&[0x20, 0x10, 0x07, 0x40]);
+        cpu.memory[0x0710..0x0712].copy_from_slice(&[0xe8, 0x60]);
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        cpu.reset();
+        cpu.step_info().unwrap();
+
+        cpu.set_irq_line(true);
+        assert_eq!(cpu.step_over(100), Ok(None));
+        assert_eq!(cpu.program_counter, 0x0703);
+        assert_eq!(cpu.register_x, 0x01);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+    }
+
+    #[test]
+    fn test_step_over_gives_up_on_subroutine_that_never_returns() {
+        let mut cpu = CPU::new();
+        let program = Asm::new().jsr("sub").brk().label("sub").jmp("sub");
+        cpu.load(program.build()).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.step_over(50), Ok(Some(StopReason::InstructionLimit)));
+        assert_eq!(cpu.program_counter, 0x0604);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x42, 0xa2, 0x07, 0x85, 0x10, 0x38, 0x48, 0x00]).unwrap();
+        cpu.set_irq_line(true);
+
+        let mut state = vec![];
+        cpu.serialize_state(&mut state);
+
+        let mut restored = CPU::new();
+        restored.deserialize_state(&state).unwrap();
+
+        assert_eq!(restored.register_a, 0x42);
+        assert_eq!(restored.register_x, 0x07);
+        assert_eq!(restored.status, cpu.status);
+        assert_eq!(restored.stack_pointer, cpu.stack_pointer);
+        assert_eq!(restored.program_counter, cpu.program_counter);
+        assert!(restored.irq_asserted());
+        assert_eq!(restored.mem_read(0x10), 0x42);
+        assert_eq!(restored.mem_read(0x01fd), 0x42);
+        assert_eq!(restored.mem_read_u16(0xFFFC), 0x0600);
+    }
+
+    #[test]
+    fn test_save_state_truncated() {
+        let mut cpu = CPU::new();
+        let mut state = vec![];
+        cpu.serialize_state(&mut state);
+        state.truncate(100);
+
+        cpu.register_a = 0x11;
+        assert_eq!(
+            cpu.deserialize_state(&state),
+            Err(StateError::Truncated {
+                expected: STATE_LEN,
+                actual: 100
+            })
+        );
+        assert_eq!(cpu.register_a, 0x11);
+    }
+
+    #[test]
+    fn test_save_state_rejects_other_versions() {
+        let mut cpu = CPU::new();
+        let mut state = vec![];
+        cpu.serialize_state(&mut state);
+
+        state[4] = STATE_VERSION + 1;
+        assert_eq!(
+            cpu.deserialize_state(&state),
+            Err(StateError::UnsupportedVersion(STATE_VERSION + 1))
+        );
+
+        state[0] = b'X';
+        assert_eq!(cpu.deserialize_state(&state), Err(StateError::BadMagic));
+    }
+
+    /// Steps a branch at `pc` and returns what `step_info` reported along
+    /// with where execution went.
+    fn branch_at(pc: u16, code: u8, offset: u8, zero: bool) -> (ExecutedInstruction, u16) {
+        let mut cpu = CPU::new();
+        cpu.memory[pc as usize] = code;
+        cpu.memory[pc as usize + 1] = offset;
+        cpu.program_counter = pc;
+        cpu.status.set(CpuFlags::ZERO, zero);
+        let executed = cpu.step_info().unwrap();
+        (executed, cpu.program_counter)
+    }
+
+    #[test]
+    fn test_branch_not_taken_falls_through() {
+        let (executed, pc) = branch_at(0x0600, 0xf0, 0x10, false);
+        assert_eq!(pc, 0x0602);
+        assert_eq!(executed.operand_address, Some(0x0612));
+        assert_eq!(executed.cycles, 2);
+    }
+
+    #[test]
+    fn test_branch_taken_within_page() {
+        let (executed, pc) = branch_at(0x0600, 0xd0, 0x02, false);
+        assert_eq!(pc, 0x0604);
+        assert_eq!(executed.operand_address, Some(0x0604));
+        assert_eq!(executed.cycles, 3);
+    }
+
+    #[test]
+    fn test_branch_taken_forward_across_page() {
+        // offset is relative to the address after the operand (0x06ff)
+        let (executed, pc) = branch_at(0x06fd, 0xd0, 0x10, false);
+        assert_eq!(pc, 0x070f);
+        assert_eq!(executed.operand_address, Some(0x070f));
+        assert_eq!(executed.cycles, 4);
+    }
+
+    #[test]
+    fn test_branch_taken_backward_across_page() {
+        let (executed, pc) = branch_at(0x0700, 0xf0, 0xfc, true);
+        assert_eq!(pc, 0x06fe);
+        assert_eq!(executed.operand_address, Some(0x06fe));
+        assert_eq!(executed.cycles, 4);
+    }
+
+    #[test]
+    fn test_run_digest_is_stable() {
+        let program = Asm::new()
+            .ldx_imm(0x05)
+            .label("loop")
+            .txa()
+            .sta_zp_x(0x10)
+            .dex()
+            .bne("loop")
+            .brk()
+            .build();
+
+        let mut cpu = CPU::new();
+        cpu.load(program.clone()).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.run_digest(100), 0xaebd_b1f3_06ad_7ed4);
+        assert_eq!(cpu.mem_read(0x15), 0x05);
+        assert_eq!(cpu.mem_read(0x11), 0x01);
+
+        let mut other = CPU::new();
+        other.load(program).unwrap();
+        other.reset();
+        other.register_y = 1;
+        assert_ne!(other.run_digest(100), 0xaebd_b1f3_06ad_7ed4);
+    }
+
+    #[test]
+    fn test_run_digest_respects_instruction_cap() {
+        let mut cpu = CPU::new();
+        // loop: jmp loop
+        cpu.load(vec![0x4c, 0x00, 0x06]).unwrap();
+        cpu.reset();
+        cpu.run_digest(10);
+        assert_eq!(cpu.instructions_retired(), 10);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = CpuError::UnsupportedOpcode {
+            opcode: 0x02,
+            pc: 0x0600,
+        };
+        assert_eq!(format!("{}", err), "unsupported opcode 0x02 at PC 0x0600");
+
+        let err = StateError::Truncated {
+            expected: 10,
+            actual: 4,
+        };
+        assert_eq!(
+            format!("{}", err),
+            "save state truncated: expected 10 bytes, got 4"
+        );
+        assert_eq!(
+            format!("{}", StateError::UnsupportedVersion(2)),
+            "unsupported save state version 2"
+        );
+
+        let boxed: Box<dyn std::error::Error> = Box::new(StateError::BadMagic);
+        assert_eq!(boxed.to_string(), "not a save state (bad magic)");
+    }
+
+    #[test]
+    fn test_anc_copies_bit_7_into_carry() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0xf0, 0x0b, 0x80, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+
+        cpu.load_and_run(vec![0x38, 0xa9, 0xf0, 0x2b, 0x70, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x70);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_alr() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0xff, 0x4b, 0x03, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_arr_carry_from_bit_6() {
+        let mut cpu = CPU::new();
+        // 0xff & 0xc0 = 0xc0, ror -> 0x60: bit 6 and bit 5 both set
+        cpu.load_and_run(vec![0xa9, 0xff, 0x6b, 0xc0, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x60);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn test_arr_overflow_from_bit_6_xor_bit_5() {
+        let mut cpu = CPU::new();
+        // 0xff & 0x80 = 0x80, ror -> 0x40: bit 6 set, bit 5 clear
+        cpu.load_and_run(vec![0xa9, 0xff, 0x6b, 0x80, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x40);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+
+        // 0xff & 0x40 = 0x40, ror -> 0x20: bit 6 clear, bit 5 set
+        cpu.load_and_run(vec![0xa9, 0xff, 0x6b, 0x40, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x20);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn test_arr_rotates_carry_in() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0xff, 0x6b, 0x00, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn test_axs() {
+        let mut cpu = CPU::new();
+        // lda #$0f; ldx #$fc; axs #$02 -> x = (0x0f & 0xfc) - 2 = 0x0a
+        cpu.load_and_run(vec![0xa9, 0x0f, 0xa2, 0xfc, 0xcb, 0x02, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0x0a);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        cpu.load_and_run(vec![0xa9, 0x0f, 0xa2, 0xfc, 0xcb, 0x0d, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0xff);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+    }
 }
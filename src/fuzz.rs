@@ -0,0 +1,130 @@
+pub use crate::cpu::CpuState;
+use crate::cpu::{CpuFlags, Mem, CPU, PROGRAM_START};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/// A panic raised inside the core while running fuzz input. The core should
+/// never panic, so each of these is a bug for the fuzzer to report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorePanic {
+    pub message: String,
+    /// State at the point the panic unwound out of the core.
+    pub state: CpuState,
+}
+
+impl fmt::Display for CorePanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "core panicked at PC {:#06x}: {}",
+            self.state.program_counter, self.message
+        )
+    }
+}
+
+impl std::error::Error for CorePanic {}
+
+/// Fuzzing entrypoint: loads `ops` at 0x0600 (ignoring
+/// `initial.program_counter`), applies the initial registers and steps up to
+/// `ops.len()` instructions, stopping early on any `StopReason`. Returns the
+/// final state, or the panic if the core raised one; fuzz targets should
+/// treat `Err` as a crash.
+pub fn fuzz_step(initial: CpuState, ops: &[u8]) -> Result<CpuState, CorePanic> {
+    let mut cpu = CPU::new();
+    let len = ops.len().min(0x10000 - PROGRAM_START as usize);
+    for (i, &op) in ops[..len].iter().enumerate() {
+        cpu.mem_write(PROGRAM_START + i as u16, op);
+    }
+
+    cpu.register_a = initial.register_a;
+    cpu.register_x = initial.register_x;
+    cpu.register_y = initial.register_y;
+    cpu.status = CpuFlags::from_bits_truncate(initial.status);
+    cpu.stack_pointer = initial.stack_pointer;
+    cpu.program_counter = PROGRAM_START;
+
+    catch_core_panic(&mut cpu, |cpu| {
+        for _ in 0..len {
+            if cpu.step().is_some() {
+                break;
+            }
+        }
+    })
+}
+
+fn catch_core_panic<F>(cpu: &mut CPU, run: F) -> Result<CpuState, CorePanic>
+where
+    F: FnOnce(&mut CPU),
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| run(cpu))) {
+        Ok(()) => Ok(cpu.cpu_state()),
+        Err(payload) => Err(CorePanic {
+            message: panic_message(payload.as_ref()),
+            state: cpu.cpu_state(),
+        }),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn initial() -> CpuState {
+        CpuState {
+            register_a: 0x10,
+            register_x: 0,
+            register_y: 0,
+            status: 0b0010_0100,
+            stack_pointer: 0xfd,
+            program_counter: 0,
+        }
+    }
+
+    #[test]
+    fn test_fuzz_step_known_sequence() {
+        // adc #$05; tax; inx; then BRK from zeroed memory
+        let state = fuzz_step(initial(), &[0x69, 0x05, 0xaa, 0xe8]).unwrap();
+        assert_eq!(state.register_a, 0x15);
+        assert_eq!(state.register_x, 0x16);
+        assert_eq!(state.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_fuzz_step_unsupported_opcode_stops_cleanly() {
+        let state = fuzz_step(initial(), &[0xe8, 0x02, 0xe8]).unwrap();
+        assert_eq!(state.register_x, 0x01);
+        assert_eq!(state.program_counter, 0x0601);
+    }
+
+    #[test]
+    fn test_fuzz_step_pc_wraps_past_top_of_memory() {
+        // jmp $ffff; then BRK from zeroed memory, fetched at the last address
+        let state = fuzz_step(initial(), &[0x4c, 0xff, 0xff]).unwrap();
+        assert_eq!(state.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_core_panic_is_reported() {
+        let mut cpu = CPU::new();
+        let err = catch_core_panic(&mut cpu, |cpu| {
+            cpu.program_counter = 0x1234;
+            panic!("boom");
+        })
+        .unwrap_err();
+
+        assert_eq!(err.message, "boom");
+        assert_eq!(err.state.program_counter, 0x1234);
+        assert_eq!(format!("{}", err), "core panicked at PC 0x1234: boom");
+    }
+}
@@ -0,0 +1,13 @@
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod asm;
+pub mod cpu;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod opcodes;
+#[cfg(test)]
+mod test_util;
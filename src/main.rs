@@ -1,20 +1,12 @@
-pub mod cpu;
-pub mod opcodes;
-use cpu::Mem;
-use cpu::CPU;
 use rand::Rng;
+use res::cpu::Mem;
+use res::cpu::CPU;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
-
 fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
@@ -126,7 +118,7 @@ fn main() {
     ];
 
     let mut cpu = CPU::new();
-    cpu.load(game_code);
+    cpu.load(game_code).unwrap();
     cpu.reset();
 
     let mut screan_state = [0 as u8; 32 * 3 * 32];
@@ -1,6 +1,7 @@
 use crate::cpu::AddressingMode;
 use std::collections::HashMap;
 
+#[derive(Debug)]
 pub struct OpCode {
   pub code: u8,
   pub mnemonic: &'static str,
@@ -19,6 +20,19 @@ impl OpCode {
       mode,
     }
   }
+
+  /// Whether indexing across a page costs this instruction an extra cycle.
+  /// Stores and read-modify-write instructions always spend that cycle, so it
+  /// is already part of their base count.
+  pub fn has_page_cross_penalty(&self) -> bool {
+    matches!(
+      self.mode,
+      AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+    ) && matches!(
+      self.mnemonic,
+      "LDA" | "LDX" | "LDY" | "AND" | "ORA" | "EOR" | "ADC" | "SBC" | "CMP"
+    )
+  }
 }
 
 lazy_static! {
@@ -204,6 +218,14 @@ lazy_static! {
     OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing),
     OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
     OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
+
+    /* unofficial */
+
+    OpCode::new(0x0b, "*ANC", 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x2b, "*ANC", 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x4b, "*ALR", 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x6b, "*ARR", 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xcb, "*AXS", 2, 2, AddressingMode::Immediate),
   ];
 
   pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
@@ -214,3 +236,96 @@ lazy_static! {
     map
   };
 }
+
+pub fn opcode_info(code: u8) -> Option<&'static OpCode> {
+  OPCODES_MAP.get(&code).copied()
+}
+
+pub fn opcode_cycles(code: u8) -> Option<u8> {
+  opcode_info(code).map(|op| op.cycles)
+}
+
+/// Checks that every entry's `len` agrees with its addressing mode and that no
+/// opcode is listed twice. `NoneAddressing` covers implied/accumulator (1),
+/// relative branches (2) and JMP/JSR (3), so only the range is checked there.
+pub fn validate_opcode_table() -> Result<(), String> {
+  let mut seen = [false; 256];
+  for op in CPU_OPS_CODES.iter() {
+    if seen[op.code as usize] {
+      return Err(format!("opcode {:#04x} ({}) is listed twice", op.code, op.mnemonic));
+    }
+    seen[op.code as usize] = true;
+
+    let valid = match op.mode {
+      AddressingMode::Immediate
+      | AddressingMode::ZeroPage
+      | AddressingMode::ZeroPage_X
+      | AddressingMode::ZeroPage_Y
+      | AddressingMode::Indirect_X
+      | AddressingMode::Indirect_Y => op.len == 2,
+      AddressingMode::Absolute | AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+        op.len == 3
+      }
+      AddressingMode::NoneAddressing => (1..=3).contains(&op.len),
+    };
+    if !valid {
+      return Err(format!(
+        "opcode {:#04x} ({}) has len {} which does not match mode {:?}",
+        op.code, op.mnemonic, op.len, op.mode
+      ));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_opcode_cycles() {
+    assert_eq!(opcode_cycles(0xa9), Some(2));
+    assert_eq!(opcode_cycles(0x02), None);
+  }
+
+  #[test]
+  fn test_opcode_info() {
+    let op = opcode_info(0xad).unwrap();
+    assert_eq!(op.mnemonic, "LDA");
+    assert_eq!(op.len, 3);
+    assert!(opcode_info(0x02).is_none());
+  }
+
+  #[test]
+  fn test_indexed_stores_have_fixed_cycles() {
+    // stores always take the indexed-addressing extra cycle, so the base count
+    // already covers a page cross and no penalty is ever added
+    assert_eq!(opcode_cycles(0x9d), Some(5)); // STA abs,X
+    assert_eq!(opcode_cycles(0x99), Some(5)); // STA abs,Y
+    assert_eq!(opcode_cycles(0x91), Some(6)); // STA (ind),Y
+    assert_eq!(opcode_cycles(0x95), Some(4)); // STA zp,X
+  }
+
+  #[test]
+  fn test_rmw_absolute_x_has_fixed_cycles() {
+    // read-modify-write always does the dummy read, page cross or not
+    for code in [0xfe, 0xde, 0x1e, 0x5e, 0x3e, 0x7e] {
+      assert_eq!(opcode_cycles(code), Some(7), "opcode {:#04x}", code);
+    }
+  }
+
+  #[test]
+  fn test_page_cross_penalty() {
+    assert!(opcode_info(0xbd).unwrap().has_page_cross_penalty()); // LDA abs,X
+    assert!(opcode_info(0xbe).unwrap().has_page_cross_penalty()); // LDX abs,Y
+    assert!(opcode_info(0xd1).unwrap().has_page_cross_penalty()); // CMP (ind),Y
+    assert!(!opcode_info(0xad).unwrap().has_page_cross_penalty()); // LDA abs
+    assert!(!opcode_info(0x9d).unwrap().has_page_cross_penalty()); // STA abs,X
+    assert!(!opcode_info(0xfe).unwrap().has_page_cross_penalty()); // INC abs,X
+  }
+
+  #[test]
+  fn test_opcode_table_is_consistent() {
+    assert_eq!(validate_opcode_table(), Ok(()));
+  }
+}
@@ -0,0 +1,161 @@
+use crate::cpu::PROGRAM_START;
+use std::collections::HashMap;
+
+enum Fixup {
+    Relative(&'static str),
+    Absolute(&'static str),
+}
+
+#[derive(Default)]
+pub struct Asm {
+    bytes: Vec<u8>,
+    labels: HashMap<&'static str, usize>,
+    fixups: Vec<(usize, Fixup)>,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Asm::default()
+    }
+
+    fn emit(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    fn emit_branch(mut self, code: u8, label: &'static str) -> Self {
+        self.bytes.push(code);
+        self.fixups.push((self.bytes.len(), Fixup::Relative(label)));
+        self.bytes.push(0);
+        self
+    }
+
+    fn emit_jump(mut self, code: u8, label: &'static str) -> Self {
+        self.bytes.push(code);
+        self.fixups.push((self.bytes.len(), Fixup::Absolute(label)));
+        self.bytes.extend_from_slice(&[0, 0]);
+        self
+    }
+
+    pub fn label(mut self, name: &'static str) -> Self {
+        if self.labels.insert(name, self.bytes.len()).is_some() {
+            panic!("label {} defined twice", name);
+        }
+        self
+    }
+
+    pub fn lda_imm(self, value: u8) -> Self {
+        self.emit(&[0xa9, value])
+    }
+
+    pub fn ldx_imm(self, value: u8) -> Self {
+        self.emit(&[0xa2, value])
+    }
+
+    pub fn sta_zp(self, addr: u8) -> Self {
+        self.emit(&[0x85, addr])
+    }
+
+    pub fn sta_zp_x(self, addr: u8) -> Self {
+        self.emit(&[0x95, addr])
+    }
+
+    pub fn txa(self) -> Self {
+        self.emit(&[0x8a])
+    }
+
+    pub fn inx(self) -> Self {
+        self.emit(&[0xe8])
+    }
+
+    pub fn dex(self) -> Self {
+        self.emit(&[0xca])
+    }
+
+    pub fn rts(self) -> Self {
+        self.emit(&[0x60])
+    }
+
+    pub fn brk(self) -> Self {
+        self.emit(&[0x00])
+    }
+
+    pub fn jmp(self, label: &'static str) -> Self {
+        self.emit_jump(0x4c, label)
+    }
+
+    pub fn jsr(self, label: &'static str) -> Self {
+        self.emit_jump(0x20, label)
+    }
+
+    pub fn bne(self, label: &'static str) -> Self {
+        self.emit_branch(0xd0, label)
+    }
+
+    pub fn beq(self, label: &'static str) -> Self {
+        self.emit_branch(0xf0, label)
+    }
+
+    /// Resolves label references and returns the program bytes, assuming the
+    /// program is loaded at 0x0600 by `CPU::load`.
+    pub fn build(mut self) -> Vec<u8> {
+        for (pos, fixup) in &self.fixups {
+            match fixup {
+                Fixup::Relative(label) => {
+                    let target = self.labels[label] as isize;
+                    let offset = target - (*pos as isize + 1);
+                    if offset < i8::MIN as isize || offset > i8::MAX as isize {
+                        panic!("branch to {} out of range ({})", label, offset);
+                    }
+                    self.bytes[*pos] = offset as i8 as u8;
+                }
+                Fixup::Absolute(label) => {
+                    let target = PROGRAM_START + self.labels[label] as u16;
+                    self.bytes[*pos] = target as u8;
+                    self.bytes[*pos + 1] = (target >> 8) as u8;
+                }
+            }
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_assemble_loop_with_backward_branch() {
+        let program = Asm::new()
+            .ldx_imm(0x05)
+            .label("loop")
+            .dex()
+            .bne("loop")
+            .brk()
+            .build();
+        assert_eq!(program, vec![0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x00]);
+
+        let mut cpu = CPU::new();
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn test_assemble_forward_branch_and_jsr() {
+        let program = Asm::new()
+            .jsr("sub")
+            .lda_imm(0x01)
+            .beq("end")
+            .sta_zp(0x10)
+            .label("end")
+            .brk()
+            .label("sub")
+            .rts()
+            .build();
+        assert_eq!(
+            program,
+            vec![0x20, 0x0a, 0x06, 0xa9, 0x01, 0xf0, 0x02, 0x85, 0x10, 0x00, 0x60]
+        );
+    }
+}